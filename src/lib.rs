@@ -7,6 +7,7 @@ extern crate typemap;
 extern crate log;
 
 use std::collections::{HashMap, VecDeque};
+use std::path::Path;
 use std::sync::Arc;
 use std::{mem, str};
 
@@ -21,10 +22,14 @@ use git2::{
     Diff,
     Tree,
     Oid,
+    Status,
+    StatusOptions,
 };
 
 pub struct GitCommit  {
     revision: String,
+    ignore_merges: bool,
+    groups: Vec<(String, Box<Fn(&Item) -> bool>)>,
 }
 
 impl GitCommit {
@@ -36,7 +41,11 @@ impl GitCommit {
     /// considered. This is useful so that only commits that have been pushed
     /// are considered.
     pub fn from_revision(revision: &str) -> GitCommit {
-        GitCommit { revision: String::from(revision) }
+        GitCommit {
+            revision: String::from(revision),
+            ignore_merges: false,
+            groups: Vec::new(),
+        }
     }
 
     /// Convenience method that uses `"HEAD"` as the revision for the above
@@ -46,7 +55,42 @@ impl GitCommit {
     /// haven't yet been pushed, which would result in broken links for example
     /// if you're linking to the commit on GitHub.
     pub fn from_head() -> GitCommit {
-        GitCommit { revision: String::from("HEAD") }
+        GitCommit {
+            revision: String::from("HEAD"),
+            ignore_merges: false,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Skip merge commits entirely instead of attributing them to the files
+    /// they resolved (git-log `--cc` combined-diff semantics). This restores
+    /// the old behavior, where merge commits are never matched: a file last
+    /// touched by a merge gets the newest non-merge commit that touched it,
+    /// and a file that only a merge commit touched gets no `LastCommit`.
+    pub fn ignore_merges(mut self) -> GitCommit {
+        self.ignore_merges = true;
+        self
+    }
+
+    /// Register an explicit glob pathspec whose matching commit is
+    /// attributed to every item for which `matches` returns `true`, instead
+    /// of the usual per-item matching of each item's own `source()`.
+    ///
+    /// This is how a directory's "last commit" (including its assets) can be
+    /// attached to every item under it: the pathspec decides what counts as
+    /// a change under git, and `matches` decides which items that change
+    /// applies to. Glob matching is only available through `group()` - an
+    /// item's own `source()` is always matched literally, since it names one
+    /// concrete file rather than a pattern.
+    ///
+    /// An item's own `source()` match always takes precedence over a
+    /// group's: if an item already has a `LastCommit` from the per-item
+    /// pass, matching groups are skipped for it.
+    pub fn group<F>(mut self, pathspec: &str, matches: F) -> GitCommit
+        where F: Fn(&Item) -> bool + 'static
+    {
+        self.groups.push((String::from(pathspec), Box::new(matches)));
+        self
     }
 }
 
@@ -55,12 +99,315 @@ pub struct LastCommit {
     pub sha: String,
     pub summary: String,
     pub time: git2::Time,
+
+    /// The full commit message, including the summary line and body.
+    pub body: String,
+
+    pub author_name: String,
+    pub author_email: String,
+    pub author_time: git2::Time,
+
+    pub committer_name: String,
+    pub committer_email: String,
+    pub committer_time: git2::Time,
 }
 
 impl typemap::Key for LastCommit {
     type Value = Arc<LastCommit>;
 }
 
+fn build_last_commit(commit: &Commit) -> LastCommit {
+    let author = commit.author();
+    let committer = commit.committer();
+
+    LastCommit {
+        sha: commit.id().to_string(),
+        summary: commit.summary().map(String::from).unwrap_or_default(),
+        time: commit.time(),
+
+        // messages and identities aren't guaranteed to be valid UTF-8 (older
+        // commits routinely carry Latin-1 names), so fall back to an empty
+        // string instead of panicking on commits we can't otherwise skip
+        body: commit.message().map(String::from).unwrap_or_default(),
+
+        author_name: author.name().map(String::from).unwrap_or_default(),
+        author_email: author.email().map(String::from).unwrap_or_default(),
+        author_time: author.when(),
+
+        committer_name: committer.name().map(String::from).unwrap_or_default(),
+        committer_email: committer.email().map(String::from).unwrap_or_default(),
+        committer_time: committer.when(),
+    }
+}
+
+/// What a commit, relative to its parent(s), looks like from the point of
+/// view of pathspec matching.
+enum MatchKind<'a> {
+    /// A root commit has no parent, so its entire tree is "new".
+    Tree(Tree<'a>),
+    /// An ordinary commit is matched against the diff with its single parent.
+    Diff(Diff<'a>),
+    /// A merge commit is matched against the diff with *every* parent; a
+    /// path only counts as touched by the merge once it shows up in all of
+    /// them (git-log `--cc` combined-diff semantics).
+    Merge(Vec<Diff<'a>>),
+}
+
+/// The OID of the blob/tree at `path` within `tree`, if it exists there.
+/// Used instead of a full diff to check whether a single path changed
+/// between two trees. Each call resolves every component of `path` in
+/// `tree`; equal subtree OIDs along the way do not end the lookup early.
+fn tree_entry_oid(tree: &Tree, path: &str) -> Option<Oid> {
+    tree.get_path(Path::new(path)).ok().map(|entry| entry.id())
+}
+
+fn match_with_parent<'a>(repo: &'a Repository, commit: &Commit, parent: &Commit,
+                         opts: &'a mut DiffOptions) -> Result<Diff<'a>, Error> {
+    let a = try!(parent.tree());
+    let b = try!(commit.tree());
+    let diff = try!(repo.diff_tree_to_tree(Some(&a), Some(&b), Some(opts)));
+    Ok(diff)
+}
+
+/// Walk `revision`'s history looking for the newest commit that matches
+/// `pathspec`, for a single pathspec that isn't tied to a specific item
+/// (used for `GitCommit`'s directory/group pathspecs).
+fn find_matching_commit(repo: &Repository, revision: &str, pathspec: &Pathspec,
+                         diffopts: &mut DiffOptions, ignore_merges: bool,
+                         flags: git2::PathspecFlags) -> diecast::Result<Option<LastCommit>> {
+    let mut revwalk = try!(repo.revwalk());
+
+    let commit = try!(repo.revparse_single(revision)
+                      .and_then(|r| r.peel(git2::ObjectType::Commit)));
+
+    try!(revwalk.push(commit.id()));
+
+    for id in revwalk {
+        let id = try!(id);
+        let commit = try!(repo.find_commit(id));
+        let parents = commit.parents().len();
+
+        if parents > 1 && ignore_merges { continue }
+
+        let is_root = parents == 0;
+
+        let match_kind =
+            if is_root {
+                MatchKind::Tree(try!(commit.tree()))
+            } else if parents > 1 {
+                let mut diffs = Vec::with_capacity(parents);
+
+                for parent in commit.parents() {
+                    diffs.push(try!(match_with_parent(repo, &commit, &parent, diffopts)));
+                }
+
+                MatchKind::Merge(diffs)
+            } else {
+                MatchKind::Diff(try!(match_with_parent(repo, &commit, &commit.parent(0).unwrap(), diffopts)))
+            };
+
+        let matched =
+            match match_kind {
+                MatchKind::Tree(ref t) => pathspec.match_tree(t, flags).is_ok(),
+                MatchKind::Diff(ref d) => pathspec.match_diff(d, flags).is_ok(),
+                MatchKind::Merge(ref ds) => ds.iter().all(|d| pathspec.match_diff(d, flags).is_ok()),
+            };
+
+        if matched {
+            return Ok(Some(build_last_commit(&commit)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Walks the full commit history of a revision, recording every commit that
+/// touched each item rather than just the most recent one.
+///
+/// This is the same revwalk + tree-OID comparison loop that `GitCommit` uses, but
+/// instead of stopping at an item's first match it keeps walking and appends
+/// every matching commit (newest-first) to that item's `FileHistory`.
+pub struct GitHistory {
+    revision: String,
+    max_commits: Option<usize>,
+    ignore_merges: bool,
+}
+
+impl GitHistory {
+    /// Determine the full commit history of a given file starting from the
+    /// specified revision. See `GitCommit::from_revision` for details on how
+    /// the revision is used.
+    pub fn from_revision(revision: &str) -> GitHistory {
+        GitHistory { revision: String::from(revision), max_commits: None, ignore_merges: false }
+    }
+
+    /// Convenience method that uses `"HEAD"` as the revision for the above
+    /// method.
+    pub fn from_head() -> GitHistory {
+        GitHistory { revision: String::from("HEAD"), max_commits: None, ignore_merges: false }
+    }
+
+    /// Cap the number of commits recorded per item, so that files with very
+    /// long histories don't blow up memory.
+    pub fn max_commits(mut self, max: usize) -> GitHistory {
+        self.max_commits = Some(max);
+        self
+    }
+
+    /// Skip merge commits entirely instead of attributing them to the files
+    /// they resolved. See `GitCommit::ignore_merges`.
+    pub fn ignore_merges(mut self) -> GitHistory {
+        self.ignore_merges = true;
+        self
+    }
+}
+
+/// Typemap key for the full, ordered (newest-first) history of commits that
+/// touched an item, as populated by `GitHistory`.
+pub struct FileHistory;
+
+impl typemap::Key for FileHistory {
+    type Value = Arc<Vec<Arc<LastCommit>>>;
+}
+
+/// The working-tree/index state of a file, as reported by
+/// `Repository::statuses`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GitFileStatus {
+    /// Unchanged relative to `HEAD`.
+    Current,
+    /// Modified in the working tree or staged in the index.
+    Modified,
+    /// New and staged in the index.
+    Added,
+    /// New, but not yet staged or tracked.
+    Untracked,
+    /// Removed from the working tree or the index.
+    Deleted,
+    /// Has unresolved merge conflicts.
+    Conflicted,
+}
+
+impl typemap::Key for GitFileStatus {
+    type Value = GitFileStatus;
+}
+
+/// Lower is more urgent. Used to pick a single `GitFileStatus` out of
+/// several matching entries deterministically, rather than whichever one a
+/// `HashMap` happens to yield first.
+fn status_precedence(status: GitFileStatus) -> u8 {
+    match status {
+        GitFileStatus::Conflicted => 0,
+        GitFileStatus::Deleted => 1,
+        GitFileStatus::Modified => 2,
+        GitFileStatus::Added => 3,
+        GitFileStatus::Untracked => 4,
+        GitFileStatus::Current => 5,
+    }
+}
+
+fn git_file_status(status: Status) -> GitFileStatus {
+    if status.contains(git2::STATUS_CONFLICTED) {
+        GitFileStatus::Conflicted
+    } else if status.contains(git2::STATUS_WT_NEW) {
+        GitFileStatus::Untracked
+    } else if status.contains(git2::STATUS_INDEX_NEW) {
+        GitFileStatus::Added
+    } else if status.contains(git2::STATUS_WT_DELETED) || status.contains(git2::STATUS_INDEX_DELETED) {
+        GitFileStatus::Deleted
+    } else if status.contains(git2::STATUS_WT_MODIFIED) || status.contains(git2::STATUS_INDEX_MODIFIED) ||
+              status.contains(git2::STATUS_WT_RENAMED) || status.contains(git2::STATUS_INDEX_RENAMED) ||
+              status.contains(git2::STATUS_WT_TYPECHANGE) || status.contains(git2::STATUS_INDEX_TYPECHANGE) {
+        GitFileStatus::Modified
+    } else {
+        GitFileStatus::Current
+    }
+}
+
+/// Attaches each item's working-tree/index `GitFileStatus`, so a site build
+/// can flag drafts or uncommitted pages without having to shell out to `git`.
+///
+/// Unlike `GitCommit`, this doesn't walk history at all - it's a thin
+/// wrapper around `Repository::statuses`, so it sees untracked and
+/// unstaged changes that `GitCommit` (with `include_untracked(false)`)
+/// never will.
+pub struct GitStatus {
+    include_untracked: bool,
+    include_ignored: bool,
+}
+
+impl GitStatus {
+    /// By default, untracked files are reported as `Untracked` and ignored
+    /// files are skipped entirely.
+    pub fn new() -> GitStatus {
+        GitStatus { include_untracked: true, include_ignored: false }
+    }
+
+    /// Whether untracked files should be reported as `Untracked` rather
+    /// than skipped.
+    pub fn include_untracked(mut self, include: bool) -> GitStatus {
+        self.include_untracked = include;
+        self
+    }
+
+    /// Whether ignored files should be reported at all.
+    pub fn include_ignored(mut self, include: bool) -> GitStatus {
+        self.include_ignored = include;
+        self
+    }
+}
+
+impl Handle<Bind> for GitStatus {
+    fn handle(&self, bind: &mut Bind) -> diecast::Result<()> {
+        let repo = match Repository::discover(".") {
+            Ok(r) => r,
+            Err(e) => {
+                trace!("(git) {:?}: {}", bind, e);
+                return Ok(());
+            },
+        };
+
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(self.include_untracked);
+        opts.recurse_untracked_dirs(self.include_untracked);
+        opts.include_ignored(self.include_ignored);
+
+        let statuses = try!(repo.statuses(Some(&mut opts)));
+
+        let mut by_path: HashMap<String, GitFileStatus> = HashMap::new();
+
+        for entry in statuses.iter() {
+            if let Some(path) = entry.path() {
+                by_path.insert(String::from(path), git_file_status(entry.status()));
+            }
+        }
+
+        for item in bind.iter_mut() {
+            // generated items have no source(), and a non-UTF-8 path can't
+            // be compared against the (UTF-8) statuses map below - neither
+            // is worth aborting the whole build over
+            let path = match item.source().and_then(|p| p.to_str()) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            // an item's path can be a page with its own entry, or a
+            // directory whose changed assets should still mark it dirty;
+            // when several entries match, report the most urgent status
+            // rather than whichever one the HashMap yields first
+            let status = by_path.iter()
+                .filter(|&(p, _)| p.as_str() == path || p.starts_with(&format!("{}/", path)))
+                .map(|(_, status)| *status)
+                .min_by_key(|&status| status_precedence(status))
+                .unwrap_or(GitFileStatus::Current);
+
+            item.extensions.insert::<GitFileStatus>(status);
+        }
+
+        Ok(())
+    }
+}
+
 impl Handle<Bind> for GitCommit {
     fn handle(&self, bind: &mut Bind) -> diecast::Result<()> {
         // since this uses discover, the git repo is looked up the
@@ -88,16 +435,12 @@ impl Handle<Bind> for GitCommit {
         diffopts.include_unreadable(false);
         diffopts.force_text(true);
 
-        let mut paths: VecDeque<(&mut Item, Pathspec)> = VecDeque::new();
+        let mut paths: VecDeque<(&mut Item, String)> = VecDeque::new();
 
         for item in bind.iter_mut() {
             let path = item.source().unwrap();
-
-            diffopts.pathspec(path.to_str().unwrap());
-
-            let p = Some(path.to_str().unwrap());
-            let pathspec = Pathspec::new(p.into_iter()).unwrap();
-            paths.push_back((item, pathspec));
+            let path = String::from(path.to_str().unwrap());
+            paths.push_back((item, path));
         }
 
         let mut revwalk = repo.revwalk().unwrap();
@@ -117,68 +460,196 @@ impl Handle<Bind> for GitCommit {
 
         for id in revwalk {
             let id = try!(id);
-            let mut commit = try!(repo.find_commit(id));
+            let commit = try!(repo.find_commit(id));
             let parents = commit.parents().len();
 
-            // ignore merge commits
-            if parents > 1 { continue }
+            // ignore merge commits entirely when asked to keep the old
+            // behavior; otherwise they're handled below by comparing tree
+            // OIDs against every parent
+            if parents > 1 && self.ignore_merges { continue }
 
             let is_root = parents == 0;
 
-            fn match_with_parent<'a>(repo: &'a Repository, commit: &Commit, parent: &Commit,
-                                     opts: &'a mut DiffOptions) -> Result<Diff<'a>, Error> {
-                let a = try!(parent.tree());
-                let b = try!(commit.tree());
-                let diff = try!(repo.diff_tree_to_tree(Some(&a), Some(&b), Some(opts)));
-                Ok(diff)
-            }
+            let tree = try!(commit.tree());
 
-            let remaining = mem::replace(&mut paths, VecDeque::new());
+            let parent_trees: Vec<Tree> = if is_root {
+                Vec::new()
+            } else {
+                let mut trees = Vec::with_capacity(parents);
 
-            let flags = git2::PATHSPEC_NO_MATCH_ERROR | git2::PATHSPEC_NO_GLOB;
+                for parent in commit.parents() {
+                    trees.push(try!(parent.tree()));
+                }
 
-            enum MatchKind<'a> {
-                Tree(Tree<'a>),
-                Diff(Diff<'a>),
-            }
+                trees
+            };
 
-            let match_kind =
-                if is_root {
-                    MatchKind::Tree(try!(commit.tree()))
-                } else {
-                    MatchKind::Diff(try!(match_with_parent(&repo, &commit, &commit.parent(0).unwrap(), &mut diffopts)))
-                };
+            let remaining = mem::replace(&mut paths, VecDeque::new());
 
             for (item, path) in remaining {
-                let matched =
-                    match match_kind {
-                        MatchKind::Tree(ref t) => path.match_tree(t, flags).is_ok(),
-                        MatchKind::Diff(ref d) => path.match_diff(d, flags).is_ok(),
+                let oid = tree_entry_oid(&tree, &path);
+
+                // a path is touched by this commit if it's new (root commit)
+                // or its blob OID differs from *every* parent's tree at that
+                // path - comparing OIDs decides this without ever
+                // materializing a diff, though each lookup still resolves
+                // the full path (see `tree_entry_oid`). A merge only
+                // counts as touching the path once every parent disagrees
+                // with it (git-log `--cc` combined-diff semantics). Renamed
+                // parents aren't detected - a rename looks like a delete + add.
+                let touched =
+                    if is_root {
+                        oid.is_some()
+                    } else {
+                        parent_trees.iter().all(|parent| tree_entry_oid(parent, &path) != oid)
                     };
 
-                if !matched {
+                if !touched {
                     paths.push_back((item, path));
                     continue
                 }
 
                 let git =
                     cache.entry(commit.id())
-                    .or_insert_with(|| {
-                        let summary = String::from(commit.summary().unwrap());
-                        let sha = commit.id().to_string();
-
-                        Arc::new(LastCommit {
-                            sha: sha,
-                            summary: summary,
-                            time: commit.time(),
-                        })
-                    })
+                    .or_insert_with(|| Arc::new(build_last_commit(&commit)))
                     .clone();
 
                 item.extensions.insert::<LastCommit>(git);
             }
         }
 
+        // directory/group pathspecs are matched against the whole history
+        // independently, then fanned out to every item the group claims
+        for &(ref pathspec, ref matches) in &self.groups {
+            let p = Some(pathspec.as_str());
+            let group_pathspec = Pathspec::new(p.into_iter()).unwrap();
+
+            let flags = git2::PATHSPEC_NO_MATCH_ERROR;
+
+            let found = try!(find_matching_commit(
+                &repo, &self.revision, &group_pathspec, &mut diffopts, self.ignore_merges, flags));
+
+            if let Some(last) = found {
+                let last = Arc::new(last);
+
+                // an item's own source() match (above) always wins over a
+                // group's, since it's the more specific of the two
+                for item in bind.iter_mut() {
+                    if matches(item) && !item.extensions.contains::<LastCommit>() {
+                        item.extensions.insert::<LastCommit>(last.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
+
+impl Handle<Bind> for GitHistory {
+    fn handle(&self, bind: &mut Bind) -> diecast::Result<()> {
+        let repo = match Repository::discover(".") {
+            Ok(r) => r,
+            Err(e) => {
+                trace!("(git) {:?}: {}", bind, e);
+                return Ok(());
+            },
+        };
+
+        let mut paths: VecDeque<(&mut Item, String, Vec<Arc<LastCommit>>)> = VecDeque::new();
+
+        for item in bind.iter_mut() {
+            // generated items have no source(), and a non-UTF-8 path can't
+            // be matched against tree entries below - neither is worth
+            // aborting the whole build over
+            let path = match item.source().and_then(|p| p.to_str()) {
+                Some(path) => String::from(path),
+                None => continue,
+            };
+
+            paths.push_back((item, path, Vec::new()));
+        }
+
+        let mut revwalk = repo.revwalk().unwrap();
+
+        let commit = try!(repo.revparse_single(&self.revision)
+                          .and_then(|r| r.peel(git2::ObjectType::Commit)));
+
+        match revwalk.push(commit.id()) {
+            Ok(_) => (),
+            Err(e) => {
+                trace!("(git): {}", e);
+                return Ok(());
+            },
+        }
+
+        let mut cache: HashMap<Oid, Arc<LastCommit>> = HashMap::new();
+
+        for id in revwalk {
+            let id = try!(id);
+            let commit = try!(repo.find_commit(id));
+            let parents = commit.parents().len();
+
+            // ignore merge commits entirely when asked to keep the old
+            // behavior; otherwise they're handled below by comparing tree
+            // OIDs against every parent
+            if parents > 1 && self.ignore_merges { continue }
+
+            let is_root = parents == 0;
+
+            let tree = try!(commit.tree());
+
+            let parent_trees: Vec<Tree> = if is_root {
+                Vec::new()
+            } else {
+                let mut trees = Vec::with_capacity(parents);
+
+                for parent in commit.parents() {
+                    trees.push(try!(parent.tree()));
+                }
+
+                trees
+            };
+
+            let remaining = mem::replace(&mut paths, VecDeque::new());
+
+            for (item, path, mut history) in remaining {
+                let oid = tree_entry_oid(&tree, &path);
+
+                let touched =
+                    if is_root {
+                        oid.is_some()
+                    } else {
+                        parent_trees.iter().all(|parent| tree_entry_oid(parent, &path) != oid)
+                    };
+
+                if touched {
+                    let git =
+                        cache.entry(commit.id())
+                        .or_insert_with(|| Arc::new(build_last_commit(&commit)))
+                        .clone();
+
+                    history.push(git);
+                }
+
+                let done = match self.max_commits {
+                    Some(max) => history.len() >= max,
+                    None => false,
+                };
+
+                if done {
+                    item.extensions.insert::<FileHistory>(Arc::new(history));
+                } else {
+                    paths.push_back((item, path, history));
+                }
+            }
+        }
+
+        for (item, _, history) in paths {
+            item.extensions.insert::<FileHistory>(Arc::new(history));
+        }
+
         Ok(())
     }
 